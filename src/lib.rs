@@ -1,14 +1,107 @@
-use log::debug;
-use metrics::gauge;
+use log::{debug, warn};
+use metrics::{counter, gauge, histogram};
 use serde_derive::Deserialize;
 use serde_this_or_that::{as_f64, as_u64};
+use sha2::Sha256;
+use std::fmt;
 use std::ops::Add;
 use std::time::{Duration, Instant};
 
+mod tr064;
+pub use tr064::fetch_tr064_data;
+
+/// Errors returned by [`login`], [`fetch_data`], and their internal helpers.
+/// A single malformed response or a transient Fritzbox reboot should be
+/// reported this way instead of taking down the request handler.
+#[derive(Debug)]
+pub enum FritzboxError {
+    Auth(String),
+    RateLimited,
+    Http(String),
+    Parse(String),
+}
+
+impl FritzboxError {
+    /// Builds an `Http` error from a response whose status code was neither
+    /// the expected one nor a transport-level failure (e.g. a 3xx from a
+    /// TLS-terminating proxy, or a 204/206 the box has no business sending).
+    pub(crate) fn unexpected_status(status: reqwest::StatusCode) -> Self {
+        FritzboxError::Http(format!("unexpected status {status}"))
+    }
+}
+
+impl fmt::Display for FritzboxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FritzboxError::Auth(msg) => write!(f, "authentication failed: {msg}"),
+            FritzboxError::RateLimited => write!(f, "rate-limited by Fritzbox"),
+            FritzboxError::Http(msg) => write!(f, "HTTP error: {msg}"),
+            FritzboxError::Parse(msg) => write!(f, "could not parse response: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for FritzboxError {}
+
+impl From<reqwest::Error> for FritzboxError {
+    fn from(err: reqwest::Error) -> Self {
+        FritzboxError::Http(err.to_string())
+    }
+}
+
+impl From<serde_xml_rs::Error> for FritzboxError {
+    fn from(err: serde_xml_rs::Error) -> Self {
+        FritzboxError::Parse(err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for FritzboxError {
+    fn from(err: serde_json::Error) -> Self {
+        FritzboxError::Parse(err.to_string())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub target: Vec<FritzboxConfig>,
+}
+
 #[derive(Deserialize)]
 pub struct FritzboxConfig {
+    pub name: String,
     user: String,
     password: String,
+    #[serde(default = "default_host")]
+    pub host: String,
+    #[serde(default)]
+    pub tls: bool,
+    #[serde(default)]
+    pub accept_invalid_certs: bool,
+    #[serde(default = "default_min_refresh_interval_secs")]
+    pub min_refresh_interval_secs: u64,
+}
+
+fn default_host() -> String {
+    String::from("fritz.box")
+}
+
+fn default_min_refresh_interval_secs() -> u64 {
+    10
+}
+
+impl FritzboxConfig {
+    fn base_url(&self) -> String {
+        let scheme = if self.tls { "https" } else { "http" };
+        format!("{scheme}://{}", self.host)
+    }
+
+    fn login_url(&self) -> String {
+        format!("{}/login_sid.lua", self.base_url())
+    }
+
+    fn data_url(&self) -> String {
+        format!("{}/data.lua", self.base_url())
+    }
 }
 
 pub struct FritzboxSession {
@@ -156,15 +249,41 @@ struct DocsisStatisticsDataWrapper {
     data: DocsisStatisticsData,
 }
 
-const LOGIN_URL: &str = "http://fritz.box/login_sid.lua";
-const DATA_URL: &str = "http://fritz.box/data.lua";
 const SESSION_TIMEOUT: Duration = Duration::from_secs(15 * 60); // Technically 20 min
 
+/// Computes the response to a PBKDF2 challenge of the form
+/// `<iter1>$<salt1>$<iter2>$<salt2>`, as issued by current FRITZ!OS
+/// firmware instead of the legacy MD5 challenge.
+fn pbkdf2_response(challenge: &str, password: &str) -> Result<String, FritzboxError> {
+    let parts: Vec<&str> = challenge.splitn(4, '$').collect();
+    let [iter1, salt1, iter2, salt2] = parts[..] else {
+        return Err(FritzboxError::Parse("malformed PBKDF2 challenge".into()));
+    };
+    let salt1_bytes =
+        hex::decode(salt1).map_err(|_| FritzboxError::Parse("malformed PBKDF2 challenge".into()))?;
+    let salt2_bytes =
+        hex::decode(salt2).map_err(|_| FritzboxError::Parse("malformed PBKDF2 challenge".into()))?;
+    let iter1: u32 = iter1
+        .parse()
+        .map_err(|_| FritzboxError::Parse("malformed PBKDF2 challenge".into()))?;
+    let iter2: u32 = iter2
+        .parse()
+        .map_err(|_| FritzboxError::Parse("malformed PBKDF2 challenge".into()))?;
+
+    let mut hash1 = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt1_bytes, iter1, &mut hash1);
+    let mut hash2 = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(&hash1, &salt2_bytes, iter2, &mut hash2);
+
+    Ok(format!("{salt2}${}", hex::encode(hash2)))
+}
+
 pub async fn login<'a>(
+    client: &reqwest::Client,
     config: &FritzboxConfig,
     session: Option<&FritzboxSession>,
-) -> Result<FritzboxSession, Box<dyn std::error::Error>> {
-    let client = reqwest::Client::new();
+) -> Result<FritzboxSession, FritzboxError> {
+    let login_url = config.login_url();
 
     // Check if the session is still valid, in which case it is extended by the
     // check.
@@ -172,7 +291,7 @@ pub async fn login<'a>(
         Some(session) => {
             debug!("Checking if session is still valid...");
             let res = client
-                .get(LOGIN_URL)
+                .get(&login_url)
                 .query(&[("sid", &session.sid)])
                 .send()
                 .await?;
@@ -190,33 +309,42 @@ pub async fn login<'a>(
 
     debug!("Getting challenge...");
     let res = client
-        .get(LOGIN_URL)
+        .get(&login_url)
         .query(&[("username", &config.user)])
         .send()
         .await?;
-    assert_eq!(res.status(), 200);
+    if res.status() != 200 {
+        return Err(FritzboxError::unexpected_status(res.status()));
+    }
     let content = res.text().await?;
     let info: SessionInfo = serde_xml_rs::from_str(&content)?;
-    let inner_response: Vec<u8> = format!("{0}-{1}", info.challenge, config.password)
-        .encode_utf16()
-        .into_iter()
-        .map(|i| i.to_le_bytes())
-        .flatten()
-        .collect();
-    let outer_response: String = format!("{0}-{1:x}", info.challenge, md5::compute(inner_response));
+    let outer_response: String = if let Some(challenge) = info.challenge.strip_prefix("2$") {
+        pbkdf2_response(challenge, &config.password)?
+    } else {
+        let inner_response: Vec<u8> = format!("{0}-{1}", info.challenge, config.password)
+            .encode_utf16()
+            .into_iter()
+            .map(|i| i.to_le_bytes())
+            .flatten()
+            .collect();
+        format!("{0}-{1:x}", info.challenge, md5::compute(inner_response))
+    };
     debug!("Logging in...");
     let res = client
-        .get(LOGIN_URL)
+        .get(&login_url)
         .query(&[("username", &config.user), ("response", &outer_response)])
         .send()
         .await?;
-    assert_eq!(res.status(), 200);
+    if res.status() != 200 {
+        return Err(FritzboxError::unexpected_status(res.status()));
+    }
     let content = res.text().await?;
     let info: SessionInfo = serde_xml_rs::from_str(&content)?;
-    assert!(
-        "0000000000000000" != info.sid,
-        "Password incorrect or Fritzbox denied access due to ratelimiting"
-    );
+    if info.sid == "0000000000000000" {
+        return Err(FritzboxError::Auth(
+            "password incorrect or Fritzbox denied access due to ratelimiting".into(),
+        ));
+    }
     Ok(FritzboxSession {
         sid: info.sid,
         valid_until: Instant::now().add(SESSION_TIMEOUT),
@@ -224,9 +352,11 @@ pub async fn login<'a>(
 }
 
 async fn fetch<T: for<'de> serde::Deserialize<'de>>(
+    client: &reqwest::Client,
+    config: &FritzboxConfig,
     session: &FritzboxSession,
     page: &str,
-) -> Result<T, Box<dyn std::error::Error>> {
+) -> Result<T, FritzboxError> {
     debug!(
         "Time left: {:?}",
         session
@@ -234,10 +364,8 @@ async fn fetch<T: for<'de> serde::Deserialize<'de>>(
             .saturating_duration_since(Instant::now())
     );
 
-    let client = reqwest::Client::new();
-    let data_url = String::from(DATA_URL);
     let res = client
-        .post(&data_url)
+        .post(config.data_url())
         .form(&[
             ("xhr", "1"),
             ("sid", &session.sid),
@@ -246,49 +374,114 @@ async fn fetch<T: for<'de> serde::Deserialize<'de>>(
         ])
         .send()
         .await?;
-    assert_eq!(res.status(), 200);
+    if res.status() == 429 {
+        return Err(FritzboxError::RateLimited);
+    }
+    if res.status() != 200 {
+        return Err(FritzboxError::unexpected_status(res.status()));
+    }
     let content = res.text().await?;
     Ok(serde_json::from_str(&content)?)
 }
 
-pub async fn fetch_data(session: &FritzboxSession) {
-    debug!("Fetching data...");
+/// Fetches and emits the DOCSIS gauges and the MSE histogram for `target`.
+/// Each sub-fetch (channel overview, channel information, channel
+/// statistics) is attempted independently: if one fails the error is counted
+/// via `fritzbox_scrape_errors_total` and the others' metrics are still
+/// served. The MSE histogram takes its channel IDs from the channel
+/// information, so it is skipped when that sub-fetch failed. Only returns
+/// `Err` when every sub-fetch failed, i.e. there is nothing fresh to serve at
+/// all.
+pub async fn fetch_data(
+    client: &reqwest::Client,
+    config: &FritzboxConfig,
+    session: &FritzboxSession,
+    target: &str,
+) -> Result<(), FritzboxError> {
+    debug!("Fetching data for target '{target}'...");
+    static TARGET: &str = "target";
 
-    let data = fetch::<DocsisConnectionDataWrapperWrapper>(&session, "docOv")
-        .await
-        .expect("Could not fetch channel overview");
-    gauge!(
-        "docsis_connection_downstream_count",
-        f64::from(data.data.connection_data.ds_count + data.data.connection_data.ds_count_second)
-    );
-    gauge!(
-        "docsis_connection_upstream_count",
-        f64::from(data.data.connection_data.us_count + data.data.connection_data.us_count_second)
-    );
+    let mut last_err = None;
+    let mut any_ok = false;
+
+    match fetch::<DocsisConnectionDataWrapperWrapper>(client, config, &session, "docOv").await {
+        Ok(data) => {
+            any_ok = true;
+            gauge!(
+                "docsis_connection_downstream_count",
+                f64::from(data.data.connection_data.ds_count + data.data.connection_data.ds_count_second),
+                TARGET => target.to_string()
+            );
+            gauge!(
+                "docsis_connection_upstream_count",
+                f64::from(data.data.connection_data.us_count + data.data.connection_data.us_count_second),
+                TARGET => target.to_string()
+            );
+        }
+        Err(err) => {
+            warn!("Could not fetch channel overview for target '{target}': {err}");
+            counter!("fritzbox_scrape_errors_total", 1, TARGET => target.to_string());
+            last_err = Some(err);
+        }
+    }
 
-    let data = fetch::<DocsisChannelDataWrapper>(&session, "docInfo")
-        .await
-        .expect("Could not fetch channel information");
     static CHANNEL: &str = "channel";
     static PROTOCOL: &str = "protocol";
-    static MODULATION: &str = "modulation";
-    for channel in data.data.channel_ds.docsis31.into_iter() {
-        static DOCSIS31: &str = "docsis31";
-        gauge!("docsis_channel_non_correctable_errors", f64::from(channel.non_corr_errors), PROTOCOL => DOCSIS31, CHANNEL => format!("{}", channel.channel_id));
-        gauge!("docsis_channel_power_level", channel.power_level, PROTOCOL => DOCSIS31, CHANNEL => format!("{}", channel.channel_id), MODULATION => format!("{}", channel.modulation));
-        gauge!("docsis_channel_mer", f64::from(u32::try_from(channel.mer).unwrap_or(0)), PROTOCOL => DOCSIS31, CHANNEL => format!("{}", channel.channel_id));
-    }
-    for channel in data.data.channel_ds.docsis30.into_iter() {
-        static DOCSIS30: &str = "docsis30";
-        gauge!("docsis_channel_non_correctable_errors", f64::from(channel.non_corr_errors), PROTOCOL => DOCSIS30, CHANNEL => format!("{}", channel.channel_id));
-        gauge!("docsis_channel_correctable_errors", f64::from(channel.corr_errors), PROTOCOL => DOCSIS30, CHANNEL => format!("{}", channel.channel_id));
-        gauge!("docsis_channel_power_level", channel.power_level, PROTOCOL => DOCSIS30, CHANNEL => format!("{}", channel.channel_id), MODULATION => format!("{}", channel.modulation));
-        gauge!("docsis_channel_mse", channel.mse, PROTOCOL => DOCSIS30, CHANNEL => format!("{}", channel.channel_id));
+    static DOCSIS30: &str = "docsis30";
+    let mut docsis30_downstream_channel_ids = Vec::new();
+
+    match fetch::<DocsisChannelDataWrapper>(client, config, &session, "docInfo").await {
+        Ok(data) => {
+            any_ok = true;
+            static MODULATION: &str = "modulation";
+            for channel in data.data.channel_ds.docsis31.into_iter() {
+                static DOCSIS31: &str = "docsis31";
+                gauge!("docsis_channel_non_correctable_errors", f64::from(channel.non_corr_errors), PROTOCOL => DOCSIS31, CHANNEL => format!("{}", channel.channel_id), TARGET => target.to_string());
+                gauge!("docsis_channel_power_level", channel.power_level, PROTOCOL => DOCSIS31, CHANNEL => format!("{}", channel.channel_id), MODULATION => format!("{}", channel.modulation), TARGET => target.to_string());
+                gauge!("docsis_channel_mer", f64::from(u32::try_from(channel.mer).unwrap_or(0)), PROTOCOL => DOCSIS31, CHANNEL => format!("{}", channel.channel_id), TARGET => target.to_string());
+            }
+            for channel in data.data.channel_ds.docsis30.into_iter() {
+                docsis30_downstream_channel_ids.push(channel.channel_id);
+                gauge!("docsis_channel_non_correctable_errors", f64::from(channel.non_corr_errors), PROTOCOL => DOCSIS30, CHANNEL => format!("{}", channel.channel_id), TARGET => target.to_string());
+                gauge!("docsis_channel_correctable_errors", f64::from(channel.corr_errors), PROTOCOL => DOCSIS30, CHANNEL => format!("{}", channel.channel_id), TARGET => target.to_string());
+                gauge!("docsis_channel_power_level", channel.power_level, PROTOCOL => DOCSIS30, CHANNEL => format!("{}", channel.channel_id), MODULATION => format!("{}", channel.modulation), TARGET => target.to_string());
+                gauge!("docsis_channel_mse", channel.mse, PROTOCOL => DOCSIS30, CHANNEL => format!("{}", channel.channel_id), TARGET => target.to_string());
+            }
+        }
+        Err(err) => {
+            warn!("Could not fetch channel information for target '{target}': {err}");
+            counter!("fritzbox_scrape_errors_total", 1, TARGET => target.to_string());
+            last_err = Some(err);
+        }
     }
 
-    /*let data = fetch::<DocsisStatisticsDataWrapper>(&session, "docStat")
-    .await
-    .expect("Could not fetch channel statistics");*/
+    match fetch::<DocsisStatisticsDataWrapper>(client, config, &session, "docStat").await {
+        Ok(data) => {
+            any_ok = true;
+            let mse_values = data.data.docsis_stats.mse_values;
+            if docsis30_downstream_channel_ids.len() != mse_values.len() {
+                warn!(
+                    "docsis30_downstream_channel_ids ({}) and mse_values ({}) length mismatch for target '{target}', skipping MSE histogram",
+                    docsis30_downstream_channel_ids.len(),
+                    mse_values.len()
+                );
+            } else {
+                for (channel_id, mse_value) in docsis30_downstream_channel_ids.iter().zip(mse_values) {
+                    histogram!("docsis_channel_mse_distribution", mse_value, PROTOCOL => DOCSIS30, CHANNEL => format!("{channel_id}"), TARGET => target.to_string());
+                }
+            }
+        }
+        Err(err) => {
+            warn!("Could not fetch channel statistics for target '{target}': {err}");
+            counter!("fritzbox_scrape_errors_total", 1, TARGET => target.to_string());
+            last_err = Some(err);
+        }
+    }
 
-    debug!("Fetching complete.")
+    debug!("Fetching complete.");
+    if any_ok {
+        Ok(())
+    } else {
+        Err(last_err.expect("at least one sub-fetch was attempted"))
+    }
 }
@@ -1,73 +1,96 @@
 use log::warn;
-use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use metrics::counter;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
 use pomfritz::*;
+use rocket::http::Status;
 use rocket::outcome::{try_outcome, Outcome};
 use rocket::request::{self, FromRequest, Request};
+use rocket::response::status::Custom;
 use rocket::State;
-use std::env;
+use std::collections::HashMap;
 use std::fs;
 use std::mem::drop;
-use std::path::PathBuf;
-use tokio::process::Command;
+use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
-use tokio::time::{sleep, Duration};
 
 #[macro_use]
 extern crate rocket;
 
 struct MyState {
     prometheus_handle: PrometheusHandle,
-    session: RwLock<FritzboxSession>,
-    config: FritzboxConfig,
+    clients: HashMap<String, reqwest::Client>,
+    sessions: HashMap<String, RwLock<FritzboxSession>>,
+    configs: HashMap<String, FritzboxConfig>,
+    last_fetch: RwLock<HashMap<String, Instant>>,
 }
 
-struct UpdateSession;
+struct UpdateSession {
+    target: String,
+}
 
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for UpdateSession {
     type Error = ();
 
     async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        let target = match req.query_value::<String>("target") {
+            Some(Ok(target)) => target,
+            _ => return Outcome::Error((Status::BadRequest, ())),
+        };
         let my_state = try_outcome!(req.guard::<&State<MyState>>().await);
-        let current_session = my_state.session.read().await;
+        let (Some(session_lock), Some(config)) = (
+            my_state.sessions.get(&target),
+            my_state.configs.get(&target),
+        ) else {
+            return Outcome::Error((Status::NotFound, ()));
+        };
+        let current_session = session_lock.read().await;
         if current_session.still_valid() {
-            return Outcome::Success(UpdateSession);
+            return Outcome::Success(UpdateSession { target });
         }
-        let new_session = login(&my_state.config, Some(&current_session))
-            .await
-            .unwrap();
+        let client = &my_state.clients[&target];
+        let new_session = match login(client, config, Some(&current_session)).await {
+            Ok(session) => session,
+            Err(err) => {
+                warn!("Could not refresh session for target '{target}': {err}");
+                counter!("fritzbox_scrape_errors_total", 1, "target" => target.clone());
+                return Outcome::Error((Status::BadGateway, ()));
+            }
+        };
         drop(current_session);
-        let mut writable_session = my_state.session.write().await;
+        let mut writable_session = session_lock.write().await;
         *writable_session = new_session;
-        Outcome::Success(UpdateSession)
+        Outcome::Success(UpdateSession { target })
     }
 }
 
-async fn get_data_from_inferior() -> Result<String, Box<dyn std::error::Error>> {
-    let client = reqwest::Client::builder()
-        .timeout(Duration::from_secs(5))
-        .build()
-        .unwrap();
-    let res = client.get("http://localhost:39714/metrics").send().await?;
-    assert_eq!(res.status(), 200);
-    let content = res.text().await?;
-    Ok(content)
-}
-
 #[get("/metrics")]
-async fn handle(state: &State<MyState>, _a: UpdateSession) -> String {
-    let inferior_data = get_data_from_inferior();
-    let session = state.session.read().await;
-    fetch_data(&session).await;
-    let my_data = state.prometheus_handle.render();
-    let result = inferior_data.await;
-    match result {
-        Ok(data) => format!("{}{}", my_data, data),
-        Err(err) => {
-            error!("Failed to fetch from Python-based exporter: {}", err);
-            my_data
+async fn handle(state: &State<MyState>, session_guard: UpdateSession) -> Custom<String> {
+    let target = &session_guard.target;
+    let config = &state.configs[target];
+    let min_refresh_interval = Duration::from_secs(config.min_refresh_interval_secs);
+
+    let now = Instant::now();
+    let needs_refresh = match state.last_fetch.read().await.get(target) {
+        Some(last) => now.duration_since(*last) >= min_refresh_interval,
+        None => true,
+    };
+    if needs_refresh {
+        let client = &state.clients[target];
+        let session = state.sessions[target].read().await;
+        match fetch_data(client, config, &session, target).await {
+            Ok(()) => {
+                state.last_fetch.write().await.insert(target.clone(), now);
+            }
+            Err(err) => {
+                warn!("Scrape failed for target '{target}': {err}");
+                counter!("fritzbox_scrape_errors_total", 1, "target" => target.clone());
+                return Custom(Status::InternalServerError, state.prometheus_handle.render());
+            }
         }
+        fetch_tr064_data(client, &config.host, config.tls, target).await;
     }
+    Custom(Status::Ok, state.prometheus_handle.render())
 }
 
 #[launch]
@@ -75,47 +98,30 @@ async fn rocket() -> _ {
     env_logger::init();
 
     let contents = fs::read_to_string("config.toml").expect("Could not read configuration file");
-    let config: FritzboxConfig =
-        toml::from_str(&contents).expect("Could not parse configuration file");
-
-    // Spawn Python-based exporter
-    tokio::spawn(async move {
-        loop {
-            let mut path = match env::current_exe() {
-                Ok(mut exe_path) => {
-                    exe_path.pop();
-                    exe_path.push("fritzbox_exporter.py");
-                    exe_path
-                }
-                Err(e) => {
-                    error!("Could not get current exe path: {e}");
-                    PathBuf::from(r"")
-                }
-            };
-            // Walk the directory tree to find the Python exporter's binary.
-            while !path.exists() {
-                path.pop();
-                path.pop();
-                path.push("fritzbox_exporter.py");
-            }
+    let config: Config = toml::from_str(&contents).expect("Could not parse configuration file");
 
-            let mut child = Command::new(path)
-                .arg("--verbose")
-                .arg("--listen=:39714")
-                .arg("--service_skiplist=WANDSLInterfaceConfig1,DeviceConfig1,X_AVM-DE_OnTel1,X_AVM-DE_Filelinks1,WANIPConnection1,WANDSLLinkConfig1,WANPPPConnection1,WANEthernetLinkConfig1")
-                .spawn()
-                .expect("Failed to spawn Python-based exporter");
-            let status = child.wait().await.expect("Failed to wait() on process");
-            warn!("Python-based exporter exited with: {}", status);
-            sleep(Duration::from_secs(3)).await;
-        }
-    });
-
-    let session = login(&config, None)
-        .await
-        .expect("Could not log into Fritzbox");
+    let mut clients = HashMap::new();
+    let mut sessions = HashMap::new();
+    let mut configs = HashMap::new();
+    for target_config in config.target {
+        let client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(target_config.accept_invalid_certs)
+            .build()
+            .expect("Could not build reqwest client");
+        let session = login(&client, &target_config, None)
+            .await
+            .unwrap_or_else(|e| panic!("Could not log into Fritzbox '{}': {e}", target_config.name));
+        clients.insert(target_config.name.clone(), client);
+        sessions.insert(target_config.name.clone(), RwLock::new(session));
+        configs.insert(target_config.name.clone(), target_config);
+    }
 
     let prometheus_handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full("docsis_channel_mse_distribution".into()),
+            &[-40.0, -35.0, -30.0, -25.0, -20.0, -15.0, -10.0, -5.0, 0.0],
+        )
+        .expect("Could not set buckets for docsis_channel_mse_distribution")
         .install_recorder()
         .expect("Could not build Prometheus recorder");
     rocket::build()
@@ -123,7 +129,9 @@ async fn rocket() -> _ {
         .mount("/", routes![handle])
         .manage(MyState {
             prometheus_handle: prometheus_handle,
-            session: RwLock::new(session),
-            config: config,
+            clients: clients,
+            sessions: sessions,
+            configs: configs,
+            last_fetch: RwLock::new(HashMap::new()),
         })
 }
@@ -0,0 +1,318 @@
+use crate::FritzboxError;
+use log::{debug, warn};
+use metrics::{counter, gauge};
+use serde_derive::Deserialize;
+
+const TR064_PORT: u16 = 49000;
+const TR064_TLS_PORT: u16 = 49443;
+
+fn tr064_base_url(host: &str, tls: bool) -> String {
+    if tls {
+        format!("https://{host}:{TR064_TLS_PORT}")
+    } else {
+        format!("http://{host}:{TR064_PORT}")
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct Tr064Service {
+    #[serde(rename = "serviceType")]
+    service_type: String,
+    #[serde(rename = "controlURL")]
+    control_url: String,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct Tr064ServiceList {
+    #[serde(rename = "service")]
+    service: Vec<Tr064Service>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct Tr064DeviceList {
+    #[serde(rename = "device")]
+    device: Vec<Tr064Device>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct Tr064Device {
+    #[serde(rename = "serviceList")]
+    service_list: Tr064ServiceList,
+    #[serde(rename = "deviceList")]
+    device_list: Tr064DeviceList,
+}
+
+#[derive(Deserialize, Debug)]
+struct Tr064Description {
+    device: Tr064Device,
+}
+
+fn collect_services(device: &Tr064Device, out: &mut Vec<Tr064Service>) {
+    out.extend(device.service_list.service.iter().cloned());
+    for child in &device.device_list.device {
+        collect_services(child, out);
+    }
+}
+
+/// Fetches the TR-064 device/service description and returns the
+/// `controlURL` of the first service whose `serviceType` matches
+/// `service_type` (e.g. `"urn:schemas-upnp-org:service:DeviceInfo:1"`).
+async fn discover_control_url(
+    client: &reqwest::Client,
+    host: &str,
+    tls: bool,
+    service_type: &str,
+) -> Result<String, FritzboxError> {
+    let res = client
+        .get(format!("{}/tr64desc.xml", tr064_base_url(host, tls)))
+        .send()
+        .await?;
+    if res.status() != 200 {
+        return Err(FritzboxError::unexpected_status(res.status()));
+    }
+    let content = res.text().await?;
+    let description: Tr064Description = serde_xml_rs::from_str(&content)?;
+    let mut services = Vec::new();
+    collect_services(&description.device, &mut services);
+    services
+        .into_iter()
+        .find(|service| service.service_type == service_type)
+        .map(|service| service.control_url)
+        .ok_or_else(|| FritzboxError::Parse(format!("No TR-064 service found for {service_type}")))
+}
+
+async fn soap_action<T: for<'de> serde::Deserialize<'de>>(
+    client: &reqwest::Client,
+    host: &str,
+    tls: bool,
+    control_url: &str,
+    service_type: &str,
+    action: &str,
+) -> Result<T, FritzboxError> {
+    let body = format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body><u:{action} xmlns:u="{service_type}"/></s:Body>
+</s:Envelope>"#
+    );
+    let res = client
+        .post(format!("{}{control_url}", tr064_base_url(host, tls)))
+        .header("Content-Type", "text/xml; charset=\"utf-8\"")
+        .header("SOAPAction", format!("{service_type}#{action}"))
+        .body(body)
+        .send()
+        .await?;
+    if res.status() != 200 {
+        return Err(FritzboxError::unexpected_status(res.status()));
+    }
+    let content = res.text().await?;
+    Ok(serde_xml_rs::from_str(&content)?)
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct GetCommonLinkPropertiesResponse {
+    #[serde(rename = "NewLayer1UpstreamMaxBitRate")]
+    new_layer1_upstream_max_bit_rate: u64,
+    #[serde(rename = "NewLayer1DownstreamMaxBitRate")]
+    new_layer1_downstream_max_bit_rate: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct GetCommonLinkPropertiesBody {
+    #[serde(rename = "GetCommonLinkPropertiesResponse")]
+    response: GetCommonLinkPropertiesResponse,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct GetCommonLinkPropertiesEnvelope {
+    body: GetCommonLinkPropertiesBody,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct DslGetInfoResponse {
+    #[serde(rename = "NewUpstreamCurrRate")]
+    new_upstream_curr_rate: u64,
+    #[serde(rename = "NewDownstreamCurrRate")]
+    new_downstream_curr_rate: u64,
+    #[serde(rename = "NewUpstreamNoiseMargin")]
+    new_upstream_noise_margin: u64,
+    #[serde(rename = "NewDownstreamNoiseMargin")]
+    new_downstream_noise_margin: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct DslGetInfoBody {
+    #[serde(rename = "GetInfoResponse")]
+    response: DslGetInfoResponse,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct DslGetInfoEnvelope {
+    body: DslGetInfoBody,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct DeviceGetInfoResponse {
+    #[serde(rename = "NewUpTime")]
+    new_up_time: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct DeviceGetInfoBody {
+    #[serde(rename = "GetInfoResponse")]
+    response: DeviceGetInfoResponse,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct DeviceGetInfoEnvelope {
+    body: DeviceGetInfoBody,
+}
+
+/// Fetches the WAN line and device uptime metrics directly over TR-064/UPnP
+/// SOAP, replacing the readings previously scraped from the spawned Python
+/// exporter.
+pub async fn fetch_tr064_data(client: &reqwest::Client, host: &str, tls: bool, target: &str) {
+    debug!("Fetching TR-064 data for target '{target}'...");
+    static TARGET: &str = "target";
+
+    match discover_control_url(
+        client,
+        host,
+        tls,
+        "urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1",
+    )
+    .await
+    {
+        Ok(control_url) => {
+            match soap_action::<GetCommonLinkPropertiesEnvelope>(
+                client,
+                host,
+                tls,
+                &control_url,
+                "urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1",
+                "GetCommonLinkProperties",
+            )
+            .await
+            {
+                Ok(envelope) => {
+                    let response = envelope.body.response;
+                    gauge!(
+                        "fritzbox_wan_upstream_max_bit_rate",
+                        f64::from(u32::try_from(response.new_layer1_upstream_max_bit_rate).unwrap_or(0)),
+                        TARGET => target.to_string()
+                    );
+                    gauge!(
+                        "fritzbox_wan_downstream_max_bit_rate",
+                        f64::from(u32::try_from(response.new_layer1_downstream_max_bit_rate).unwrap_or(0)),
+                        TARGET => target.to_string()
+                    );
+                }
+                Err(err) => {
+                    warn!("Could not fetch WANCommonInterfaceConfig for target '{target}': {err}");
+                    counter!("fritzbox_scrape_errors_total", 1, TARGET => target.to_string());
+                }
+            }
+        }
+        Err(err) => {
+            warn!("Could not discover WANCommonInterfaceConfig for target '{target}': {err}");
+            counter!("fritzbox_scrape_errors_total", 1, TARGET => target.to_string());
+        }
+    }
+
+    match discover_control_url(
+        client,
+        host,
+        tls,
+        "urn:schemas-upnp-org:service:WANDSLInterfaceConfig:1",
+    )
+    .await
+    {
+        Ok(control_url) => {
+            match soap_action::<DslGetInfoEnvelope>(
+                client,
+                host,
+                tls,
+                &control_url,
+                "urn:schemas-upnp-org:service:WANDSLInterfaceConfig:1",
+                "GetInfo",
+            )
+            .await
+            {
+                Ok(envelope) => {
+                    let response = envelope.body.response;
+                    gauge!(
+                        "fritzbox_dsl_upstream_curr_rate",
+                        f64::from(u32::try_from(response.new_upstream_curr_rate).unwrap_or(0)),
+                        TARGET => target.to_string()
+                    );
+                    gauge!(
+                        "fritzbox_dsl_downstream_curr_rate",
+                        f64::from(u32::try_from(response.new_downstream_curr_rate).unwrap_or(0)),
+                        TARGET => target.to_string()
+                    );
+                    gauge!(
+                        "fritzbox_dsl_upstream_noise_margin",
+                        f64::from(u32::try_from(response.new_upstream_noise_margin).unwrap_or(0)),
+                        TARGET => target.to_string()
+                    );
+                    gauge!(
+                        "fritzbox_dsl_downstream_noise_margin",
+                        f64::from(u32::try_from(response.new_downstream_noise_margin).unwrap_or(0)),
+                        TARGET => target.to_string()
+                    );
+                }
+                Err(err) => {
+                    warn!("Could not fetch WANDSLInterfaceConfig for target '{target}': {err}");
+                    counter!("fritzbox_scrape_errors_total", 1, TARGET => target.to_string());
+                }
+            }
+        }
+        Err(err) => {
+            warn!("Could not discover WANDSLInterfaceConfig for target '{target}': {err}");
+            counter!("fritzbox_scrape_errors_total", 1, TARGET => target.to_string());
+        }
+    }
+
+    match discover_control_url(client, host, tls, "urn:schemas-upnp-org:service:DeviceInfo:1").await
+    {
+        Ok(control_url) => {
+            match soap_action::<DeviceGetInfoEnvelope>(
+                client,
+                host,
+                tls,
+                &control_url,
+                "urn:schemas-upnp-org:service:DeviceInfo:1",
+                "GetInfo",
+            )
+            .await
+            {
+                Ok(envelope) => {
+                    gauge!(
+                        "fritzbox_uptime_seconds",
+                        f64::from(u32::try_from(envelope.body.response.new_up_time).unwrap_or(0)),
+                        TARGET => target.to_string()
+                    );
+                }
+                Err(err) => {
+                    warn!("Could not fetch DeviceInfo for target '{target}': {err}");
+                    counter!("fritzbox_scrape_errors_total", 1, TARGET => target.to_string());
+                }
+            }
+        }
+        Err(err) => {
+            warn!("Could not discover DeviceInfo for target '{target}': {err}");
+            counter!("fritzbox_scrape_errors_total", 1, TARGET => target.to_string());
+        }
+    }
+
+    debug!("TR-064 fetch complete.");
+}